@@ -1,11 +1,11 @@
 use itertools::Itertools;
 use log::{debug, trace};
-use num_rational::Ratio;
-use num_traits::cast::ToPrimitive;
 use rand::seq::IteratorRandom;
-use rand::Rng;
-use rayon::iter::repeatn;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
 use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 
 const YEAR_TIME: u32 = 365 * 24 * 60; // in minutes
 const UNAVAILABLE_TIME_AFTER_BAD: u32 = 9;
@@ -15,9 +15,108 @@ const RELEASE_COUNT: usize = 30;
 const BAD_RELEASE_COUNT: usize = 3;
 const MIN_TIME_BETWEEN_RELEASES: u32 = 60;
 
-fn choice_times_to_releases() -> [u32; RELEASE_COUNT] {
+const SERVICE_A: usize = 0;
+const SERVICE_B: usize = 1;
+const SERVICE_C: usize = 2;
+
+/// Longest gap, in minutes, between two candidate arrivals that [`TokenBucket`]
+/// uses to probe for a free token;
+/// kept short relative to `MIN_TIME_BETWEEN_RELEASES` so clusters can form
+/// within a single throttling window instead of only at its boundary.
+const TOKEN_BUCKET_ARRIVAL_TICK: u32 = 5;
+
+/// A strategy for generating the `RELEASE_COUNT` release timestamps (in
+/// minutes into the year) for one service's release history.
+trait ReleaseSchedule {
+    fn generate(&self, rng: &mut impl Rng) -> [u32; RELEASE_COUNT];
+}
+
+/// Rejection-samples release times uniformly over the year, retrying until
+/// each is more than `MIN_TIME_BETWEEN_RELEASES` away from every release
+/// already chosen. This is the schedule the simulator originally shipped with.
+struct UniformMinGap;
+
+impl ReleaseSchedule for UniformMinGap {
+    fn generate(&self, rng: &mut impl Rng) -> [u32; RELEASE_COUNT] {
+        choice_times_to_releases(rng)
+    }
+}
+
+/// Token-bucket (leaky-bucket) release cadence: tokens refill at
+/// `refill_rate` per minute up to `burst_capacity`, and a release is only
+/// emitted once a token is available. Candidate arrivals come after random
+/// gaps of 1 to `TOKEN_BUCKET_ARRIVAL_TICK` minutes, so releases cluster while the bucket
+/// is full and throttle back to the refill rate once it empties, unlike the
+/// evenly-spaced `UniformMinGap`.
+struct TokenBucket {
+    refill_rate: f64,
+    burst_capacity: f64,
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        TokenBucket {
+            refill_rate: 1.0 / MIN_TIME_BETWEEN_RELEASES as f64,
+            burst_capacity: 5.0,
+        }
+    }
+}
+
+impl ReleaseSchedule for TokenBucket {
+    fn generate(&self, rng: &mut impl Rng) -> [u32; RELEASE_COUNT] {
+        let mut ans = [0; RELEASE_COUNT];
+        let mut tokens = self.burst_capacity;
+        let mut time = 0u32;
+        for i in 0..RELEASE_COUNT {
+            loop {
+                let gap = rng.gen_range(1..=TOKEN_BUCKET_ARRIVAL_TICK);
+                time += gap;
+                tokens = (tokens + self.refill_rate * gap as f64).min(self.burst_capacity);
+                if tokens >= 1.0 {
+                    tokens -= 1.0;
+                    break;
+                }
+            }
+            // Clamp into the year while leaving one free minute for each release
+            // still to come, so timestamps stay strictly increasing and none is
+            // re-emitted: a low enough `refill_rate` can otherwise push releases
+            // past the end of the year, and clamping them all to its last minute
+            // would hand back duplicate slots, which would trip the distinctness
+            // assert in `create_releases_times`.
+            ans[i] = time.min(YEAR_TIME - (RELEASE_COUNT - i) as u32);
+            time = ans[i];
+        }
+        ans
+    }
+}
+
+/// The two release schedules selectable at runtime; see [`release_schedule`].
+enum ReleaseScheduleKind {
+    UniformMinGap(UniformMinGap),
+    TokenBucket(TokenBucket),
+}
+
+impl ReleaseSchedule for ReleaseScheduleKind {
+    fn generate(&self, rng: &mut impl Rng) -> [u32; RELEASE_COUNT] {
+        match self {
+            ReleaseScheduleKind::UniformMinGap(s) => s.generate(rng),
+            ReleaseScheduleKind::TokenBucket(s) => s.generate(rng),
+        }
+    }
+}
+
+/// Picks the release schedule from the `RELEASE_SCHEDULE` env var
+/// (`"token_bucket"` or `"uniform"`), defaulting to `"uniform"` so existing
+/// runs are unaffected unless a user opts in to comparing cadences.
+fn release_schedule() -> ReleaseScheduleKind {
+    match std::env::var("RELEASE_SCHEDULE").as_deref() {
+        Ok("token_bucket") => ReleaseScheduleKind::TokenBucket(TokenBucket::default()),
+        _ => ReleaseScheduleKind::UniformMinGap(UniformMinGap),
+    }
+}
+
+fn choice_times_to_releases(rng: &mut impl Rng) -> [u32; RELEASE_COUNT] {
     let mut ans = [0; RELEASE_COUNT];
-    let mut rng = rand::thread_rng();
     for i in 0..RELEASE_COUNT {
         let mut time;
         loop {
@@ -37,179 +136,273 @@ fn is_correct_time_to_append(arr: &[u32], time: u32) -> bool {
         .all(|&x| time.abs_diff(x) > MIN_TIME_BETWEEN_RELEASES)
 }
 
-fn choice_bad_times_to_releases(releases: &[u32; RELEASE_COUNT]) -> [u32; BAD_RELEASE_COUNT] {
+fn choice_bad_times_to_releases(
+    releases: &[u32; RELEASE_COUNT],
+    rng: &mut impl Rng,
+) -> [u32; BAD_RELEASE_COUNT] {
     let mut ans = [0; BAD_RELEASE_COUNT];
-    releases
-        .iter()
-        .copied()
-        .choose_multiple_fill(&mut rand::thread_rng(), &mut ans);
+    releases.iter().copied().choose_multiple_fill(rng, &mut ans);
     ans
 }
 
-fn create_releases_times() -> ([u32; RELEASE_COUNT], [u32; BAD_RELEASE_COUNT]) {
-    let mut releases = choice_times_to_releases();
-    let mut bad_releases = choice_bad_times_to_releases(&releases);
+fn create_releases_times(
+    rng: &mut impl Rng,
+    schedule: &impl ReleaseSchedule,
+) -> ([u32; RELEASE_COUNT], [u32; BAD_RELEASE_COUNT]) {
+    let mut releases = schedule.generate(rng);
+    let mut bad_releases = choice_bad_times_to_releases(&releases, rng);
     releases.sort_unstable();
     bad_releases.sort_unstable();
     debug_assert!(bad_releases.iter().dedup().count() == BAD_RELEASE_COUNT);
     (releases, bad_releases)
 }
 
-fn experiment() -> u32 {
-    let (a_rel, a_bad_rel) = create_releases_times();
-    let (b_rel, b_bad_rel) = create_releases_times();
-    let (c_rel, c_bad_rel) = create_releases_times();
-    trace!("A releases: {:?}, bad: {:?}", &a_rel, &a_bad_rel);
-    trace!("B releases: {:?}, bad: {:?}", &b_rel, &b_bad_rel);
-    trace!("C releases: {:?}, bad: {:?}", &c_rel, &c_bad_rel);
-
-    let mut time;
-    let mut b_cache_full_in = Some(TIME_TO_B_CACHE);
-    let mut c_cache_full_in = Some(TIME_TO_C_CACHE);
-    let mut downs = [(0, 0); 3 * BAD_RELEASE_COUNT];
-    let mut downs_count = 0;
-    let mut a_available_in = None;
-    let mut b_available_in = None;
-    let mut c_available_in = None;
-
-    let (mut a, mut a_bad) = (
-        a_rel.into_iter().peekable(),
-        a_bad_rel.into_iter().peekable(),
-    );
-    let (mut b, mut b_bad) = (
-        b_rel.into_iter().peekable(),
-        b_bad_rel.into_iter().peekable(),
-    );
-    let (mut c, mut c_bad) = (
-        c_rel.into_iter().peekable(),
-        c_bad_rel.into_iter().peekable(),
-    );
-
-    debug!("Start simulation");
-    loop {
-        let available_before = is_now_available(
-            a_available_in,
-            b_available_in,
-            c_available_in,
-            b_cache_full_in,
-            c_cache_full_in,
-        );
+/// A discrete-event simulator event, ordered in the heap by the timestamp it is
+/// scheduled under; events sharing a timestamp pop in variant declaration order,
+/// then by service index (the derived `Ord`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Event {
+    Release(usize),
+    BadRelease(usize),
+    CacheFilled(usize),
+    BecameAvailable(usize),
+}
 
-        let events = [
-            a.peek().copied(),
-            b.peek().copied(),
-            c.peek().copied(),
-            a_bad.peek().copied(),
-            b_bad.peek().copied(),
-            c_bad.peek().copied(),
-            b_cache_full_in,
-            c_cache_full_in,
-            a_available_in,
-            b_available_in,
-            c_available_in,
-        ];
-        time = match events.iter().filter_map(|x| *x).min() {
-            Some(val) => val,
-            // end of the actions
-            None => {
-                debug!("End of the simulation");
-                break;
-            }
-        };
+/// Describes the services taking part in the simulation and how a bad release
+/// on one of them ripples into the others, replacing the old hardcoded A/B/C
+/// shape with configurable data.
+struct Topology {
+    num_services: usize,
+    /// `cache_invalidated_by[upstream]` lists the `(dependent, fill_time)` pairs
+    /// whose cache goes cold, and takes `fill_time` minutes to warm back up,
+    /// whenever `upstream` has a bad release.
+    cache_invalidated_by: Vec<Vec<(usize, u32)>>,
+    /// Warm-up fill time for each service's cache at the start of the
+    /// simulation; `None` for services with no cache of their own, which makes
+    /// them critical (unshielded) for their entire downtime.
+    initial_cache_fill: Vec<Option<u32>>,
+}
 
-        // common releases
-        for it in [&mut a, &mut b, &mut c] {
-            if it.peek().copied() == Some(time) {
-                it.next();
-            }
+impl Topology {
+    /// The topology this simulator originally shipped with: three services,
+    /// where a bad release of A invalidates the caches B and C use to ride out
+    /// their own downtime.
+    fn three_service_default() -> Self {
+        let mut cache_invalidated_by = vec![Vec::new(); 3];
+        cache_invalidated_by[SERVICE_A].push((SERVICE_B, TIME_TO_B_CACHE));
+        cache_invalidated_by[SERVICE_A].push((SERVICE_C, TIME_TO_C_CACHE));
+        Topology {
+            num_services: 3,
+            cache_invalidated_by,
+            initial_cache_fill: vec![None, Some(TIME_TO_B_CACHE), Some(TIME_TO_C_CACHE)],
         }
+    }
+}
 
-        // cache full time and unavailable time
-        for item in [
-            &mut b_cache_full_in,
-            &mut c_cache_full_in,
-            &mut a_available_in,
-            &mut b_available_in,
-            &mut c_available_in,
-        ] {
-            if *item == Some(time) {
-                *item = None;
-            }
-        }
+/// Runs a single experiment whose entire random history is determined by `seed`,
+/// so a failing assertion can be reproduced by re-running with the printed seed.
+fn experiment(seed: u64, schedule: &ReleaseScheduleKind) -> u32 {
+    let mut rng = ChaCha20Rng::seed_from_u64(seed);
+    let topology = Topology::three_service_default();
+    let releases: Vec<([u32; RELEASE_COUNT], [u32; BAD_RELEASE_COUNT])> = (0..topology
+        .num_services)
+        .map(|_| create_releases_times(&mut rng, schedule))
+        .collect();
+    for (svc, (rel, bad_rel)) in releases.iter().enumerate() {
+        trace!("Service {} releases: {:?}, bad: {:?}", svc, rel, bad_rel);
+    }
 
-        // bad releases
-        if a_bad.peek().copied() == Some(time) {
-            trace!("A bad release at {}", time);
-            a_bad.next();
-            a_available_in = Some(time + UNAVAILABLE_TIME_AFTER_BAD);
-            b_cache_full_in = Some(time + TIME_TO_B_CACHE);
-            c_cache_full_in = Some(time + TIME_TO_C_CACHE);
+    let mut heap: BinaryHeap<Reverse<(u32, Event)>> = BinaryHeap::new();
+    for (svc, (rel, bad_rel)) in releases.iter().enumerate() {
+        for &t in rel {
+            heap.push(Reverse((t, Event::Release(svc))));
         }
-        if b_bad.peek().copied() == Some(time) {
-            trace!("B bad release at {}", time);
-            b_bad.next();
-            b_available_in = Some(time + UNAVAILABLE_TIME_AFTER_BAD);
+        for &t in bad_rel {
+            heap.push(Reverse((t, Event::BadRelease(svc))));
         }
-        if c_bad.peek().copied() == Some(time) {
-            trace!("C bad release at {}", time);
-            c_bad.next();
-            c_available_in = Some(time + UNAVAILABLE_TIME_AFTER_BAD);
+    }
+    for (svc, fill) in topology.initial_cache_fill.iter().enumerate() {
+        if let Some(fill) = fill {
+            heap.push(Reverse((*fill, Event::CacheFilled(svc))));
         }
+    }
 
-        // analyze downtime
-        let available_after = is_now_available(
-            a_available_in,
-            b_available_in,
-            c_available_in,
-            b_cache_full_in,
-            c_cache_full_in,
-        );
+    let mut available_in: Vec<Option<u32>> = vec![None; topology.num_services];
+    let mut cache_full_in: Vec<Option<u32>> = topology.initial_cache_fill.clone();
+    let mut downs: Vec<(u32, u32)> = Vec::with_capacity(topology.num_services * BAD_RELEASE_COUNT);
+    let mut down_since: Option<u32> = None;
+    let mut was_available = is_now_available(&topology, &available_in, &cache_full_in);
 
-        if available_before && !available_after {
+    debug!("Start simulation");
+    while let Some(Reverse((time, event))) = heap.pop() {
+        match event {
+            Event::Release(svc) => trace!("Service {} release at {}", svc, time),
+            Event::BadRelease(svc) => {
+                trace!("Service {} bad release at {}", svc, time);
+                available_in[svc] = Some(time + UNAVAILABLE_TIME_AFTER_BAD);
+                heap.push(Reverse((
+                    time + UNAVAILABLE_TIME_AFTER_BAD,
+                    Event::BecameAvailable(svc),
+                )));
+                for &(dependent, fill_time) in &topology.cache_invalidated_by[svc] {
+                    cache_full_in[dependent] = Some(time + fill_time);
+                    heap.push(Reverse((time + fill_time, Event::CacheFilled(dependent))));
+                }
+            }
+            // Stale events (superseded by a later re-schedule of the same service)
+            // are ignored by checking the state still points at this timestamp.
+            Event::CacheFilled(svc) => {
+                if cache_full_in[svc] == Some(time) {
+                    cache_full_in[svc] = None;
+                }
+            }
+            Event::BecameAvailable(svc) => {
+                if available_in[svc] == Some(time) {
+                    available_in[svc] = None;
+                }
+            }
+        }
+
+        let is_available = is_now_available(&topology, &available_in, &cache_full_in);
+        if was_available && !is_available {
             trace!("Down at {}", time);
-            downs[downs_count].0 = time;
-        } else if !available_before && available_after {
+            down_since = Some(time);
+        } else if !was_available && is_available {
             trace!("Up at {}", time);
-            downs[downs_count].1 = time;
-            downs_count += 1;
+            downs.push((down_since.take().unwrap(), time));
         }
+        was_available = is_available;
     }
+    debug!("End of the simulation");
 
-    debug!("Downs: {:?}", &downs[0..downs_count]);
-    let down_time = downs.into_iter().map(|(start, end)| end - start).sum();
-    debug_assert!(down_time <= 3 * BAD_RELEASE_COUNT as u32 * UNAVAILABLE_TIME_AFTER_BAD);
-    debug_assert!(down_time >= BAD_RELEASE_COUNT as u32 * UNAVAILABLE_TIME_AFTER_BAD);
+    debug!("Downs: {:?}", &downs);
+    let down_time: u32 = downs.into_iter().map(|(start, end)| end - start).sum();
+    debug_assert!(
+        down_time
+            <= topology.num_services as u32 * BAD_RELEASE_COUNT as u32 * UNAVAILABLE_TIME_AFTER_BAD,
+        "seed={}: down_time {} exceeded the theoretical max",
+        seed,
+        down_time
+    );
+    // The lower bound only holds for a schedule whose `MIN_TIME_BETWEEN_RELEASES`
+    // gap is wider than `UNAVAILABLE_TIME_AFTER_BAD`: `UniformMinGap` can never
+    // cluster A's bad releases close enough for their `[t, t+9]` windows to
+    // overlap, but `TokenBucket`'s bursts can, which legitimately pushes the
+    // union of downtime windows below `BAD_RELEASE_COUNT * UNAVAILABLE_TIME_AFTER_BAD`.
+    if let ReleaseScheduleKind::UniformMinGap(_) = schedule {
+        debug_assert!(
+            down_time >= BAD_RELEASE_COUNT as u32 * UNAVAILABLE_TIME_AFTER_BAD,
+            "seed={}: down_time {} below the theoretical min",
+            seed,
+            down_time
+        );
+    }
     down_time
 }
 
+/// A service is critical right now if it is down and either has no cache to
+/// fall back on, or its cache has gone cold too; the system is available only
+/// while no service is in that state.
 fn is_now_available(
-    a_available_in: Option<u32>,
-    b_available_in: Option<u32>,
-    c_available_in: Option<u32>,
-    b_cache_full_in: Option<u32>,
-    c_cache_full_in: Option<u32>,
+    topology: &Topology,
+    available_in: &[Option<u32>],
+    cache_full_in: &[Option<u32>],
 ) -> bool {
-    a_available_in.is_none()
-        && (b_available_in.is_none() || b_cache_full_in.is_none())
-        && (c_available_in.is_none() || c_cache_full_in.is_none())
+    (0..topology.num_services).all(|svc| {
+        let has_cache = topology.initial_cache_fill[svc].is_some();
+        available_in[svc].is_none() || (has_cache && cache_full_in[svc].is_none())
+    })
 }
 
 const EXPERIMENT_COUNT: usize = 10_000_000;
+const CONFIDENCE_Z_95: f64 = 1.96;
+
+/// Online (Welford) mean/variance accumulator that composes under a parallel
+/// reduce: samples are folded in with [`Self::push`], and partial
+/// accumulators are combined with [`Self::merge`] instead of re-scanning every
+/// sample, which is what makes it usable as a rayon `reduce` identity/op pair.
+///
+/// Note: `mean`/`m2` are `f64`, and rayon's `reduce` merges partials in a tree
+/// shape that depends on how the work is split across threads, so the final
+/// bits of `mean`/`m2` (and thus the printed half-width) are not guaranteed
+/// identical across thread counts, even though every per-experiment sample is
+/// (the per-experiment RNG is seeded independently of thread count). Only
+/// this last floating-point aggregation step is reduction-order-sensitive.
+#[derive(Debug, Clone, Copy, Default)]
+struct WelfordAccumulator {
+    n: u64,
+    mean: f64,
+    m2: f64,
+}
+
+impl WelfordAccumulator {
+    fn push(mut self, x: f64) -> Self {
+        self.n += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.n as f64;
+        self.m2 += delta * (x - self.mean);
+        self
+    }
+
+    fn merge(self, other: Self) -> Self {
+        if self.n == 0 {
+            return other;
+        }
+        if other.n == 0 {
+            return self;
+        }
+        let n = self.n + other.n;
+        let delta = other.mean - self.mean;
+        let mean = (self.n as f64 * self.mean + other.n as f64 * other.mean) / n as f64;
+        let m2 = self.m2 + other.m2
+            + delta * delta * (self.n as f64 * other.n as f64) / n as f64;
+        WelfordAccumulator { n, mean, m2 }
+    }
+
+    fn sample_variance(&self) -> f64 {
+        self.m2 / (self.n - 1) as f64
+    }
+
+    fn standard_error(&self) -> f64 {
+        (self.sample_variance() / self.n as f64).sqrt()
+    }
+
+    /// Half-width of the 95% confidence interval around `self.mean`.
+    fn confidence_half_width(&self) -> f64 {
+        CONFIDENCE_Z_95 * self.standard_error()
+    }
+}
+
+/// Reads the base seed from (in priority order) the first CLI argument, then the
+/// `EXPERIMENT_SEED` env var, falling back to a fixed default so a bare `cargo run`
+/// still gives a reproducible campaign.
+fn base_seed() -> u64 {
+    std::env::args()
+        .nth(1)
+        .or_else(|| std::env::var("EXPERIMENT_SEED").ok())
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0)
+}
 
 fn main() {
     env_logger::init();
-    let unavailable_sum: u64 = repeatn((), EXPERIMENT_COUNT)
-        .map(|_| experiment() as u64)
-        .sum();
-    println!("Unavailable sum: {}", unavailable_sum);
-    let average_unavailable = Ratio::<u64>::new(unavailable_sum, EXPERIMENT_COUNT as u64);
+    let base_seed = base_seed();
+    // Printed unconditionally (not just via `debug!`) so a panicking run is
+    // reproducible even under default logging: re-run with `EXPERIMENT_SEED`
+    // set to this value to replay the exact same campaign.
+    println!("Base seed: {}", base_seed);
+    let schedule = release_schedule();
+    let stats: WelfordAccumulator = (0..EXPERIMENT_COUNT as u64)
+        .into_par_iter()
+        .map(|i| WelfordAccumulator::default().push(experiment(base_seed ^ i, &schedule) as f64))
+        .reduce(WelfordAccumulator::default, WelfordAccumulator::merge);
+
+    let half_width = stats.confidence_half_width();
     println!(
-        "Average unavailable time: {} ({})",
-        average_unavailable,
-        average_unavailable.to_f64().unwrap()
+        "Average unavailable time: {:.4} ± {:.4} minutes (95% CI, n={})",
+        stats.mean, half_width, stats.n
     );
-    let percent = Ratio::from_integer(100_u64)
-        * (Ratio::from_integer(1_u64)
-            - average_unavailable / Ratio::from_integer(YEAR_TIME as u64));
-    println!("Percent: {}({})", percent, percent.to_f64().unwrap());
+    let percent = 100.0 * (1.0 - stats.mean / YEAR_TIME as f64);
+    let percent_half_width = 100.0 * half_width / YEAR_TIME as f64;
+    println!("Percent available: {:.6}% ± {:.6}%", percent, percent_half_width);
 }